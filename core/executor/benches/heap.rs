@@ -0,0 +1,64 @@
+// Copyright 2019 Parity Technologies (UK) Ltd.
+// This file is part of Substrate.
+
+// Substrate is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Substrate is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Substrate.  If not, see <http://www.gnu.org/licenses/>.
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use substrate_executor::heap::Heap;
+
+const HEAP_SIZE: usize = 16 * 1024 * 1024;
+
+fn heap_benchmark(c: &mut Criterion) {
+	c.bench_function("allocate and deallocate small block", |b| {
+		let mut heap = Heap::new(0);
+
+		b.iter(|| {
+			let ptr = heap.allocate(16).unwrap();
+			heap.deallocate(ptr);
+			if heap.used() >= HEAP_SIZE {
+				heap.reset();
+			}
+		})
+	});
+
+	c.bench_function("allocate until 16 MiB are used", |b| {
+		let mut heap = Heap::new(0);
+
+		b.iter(|| {
+			heap.reset();
+			while heap.used() < HEAP_SIZE {
+				heap.allocate(64).unwrap();
+			}
+		})
+	});
+
+	c.bench_function("allocate and deallocate in LIFO order", |b| {
+		let mut heap = Heap::new(0);
+
+		b.iter(|| {
+			let ptrs: Vec<u32> = [8, 64, 512, 4096].iter()
+				.map(|size| heap.allocate(*size).unwrap())
+				.collect();
+			for ptr in ptrs.into_iter().rev() {
+				heap.deallocate(ptr);
+			}
+			if heap.used() >= HEAP_SIZE {
+				heap.reset();
+			}
+		})
+	});
+}
+
+criterion_group!(benches, heap_benchmark);
+criterion_main!(benches);
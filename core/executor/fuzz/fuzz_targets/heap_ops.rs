@@ -0,0 +1,68 @@
+// Copyright 2019 Parity Technologies (UK) Ltd.
+// This file is part of Substrate.
+
+// Substrate is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Substrate is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Substrate.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Drives the heap with a random sequence of allocations and deallocations.
+//!
+//! The input is read in chunks of 5 bytes: one op byte followed by a little endian
+//! `u32` argument. An even op byte allocates `argument` bytes, an odd one frees
+//! the live block at index `argument % live.len()`.
+
+#![no_main]
+
+use std::cmp;
+use libfuzzer_sys::fuzz_target;
+use substrate_executor::heap::{AllocError, Heap};
+
+const RESERVED: u32 = 1024;
+
+fuzz_target!(|data: &[u8]| {
+	let mut heap = Heap::new(RESERVED);
+	let mut live: Vec<(u32, u32)> = Vec::new();
+	let mut used = 0usize;
+
+	for op in data.chunks_exact(5) {
+		let argument = u32::from(op[1])
+			| u32::from(op[2]) << 8
+			| u32::from(op[3]) << 16
+			| u32::from(op[4]) << 24;
+
+		if op[0] % 2 == 0 {
+			// Zero sized requests are backed by a single byte.
+			let size = cmp::max(argument, 1);
+			match heap.allocate(argument) {
+				Ok(ptr) => {
+					assert!(ptr >= RESERVED);
+					let end = ptr.checked_add(size).expect("block must be addressable");
+					for &(other, other_size) in &live {
+						assert!(end <= other || other + other_size <= ptr, "blocks overlap");
+					}
+					live.push((ptr, size));
+					used += size as usize;
+				},
+				Err(AllocError::HeapExhausted { requested, available }) => {
+					assert_eq!(requested, size as usize);
+					assert!(requested > available);
+				},
+				Err(e) => panic!("unexpected allocation error: {:?}", e),
+			}
+		} else if !live.is_empty() {
+			let (ptr, _) = live.swap_remove(argument as usize % live.len());
+			heap.deallocate(ptr);
+		}
+
+		assert_eq!(heap.used(), used);
+	}
+});
@@ -18,9 +18,77 @@
 
 //! This module implements a linear allocation heap.
 
+use std::cmp;
+use std::num::NonZeroU32;
+use serde_derive::Serialize;
+
+/// Error which can occur while allocating from the heap.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AllocError {
+	/// The remaining address space can not fit the requested number of bytes.
+	HeapExhausted {
+		/// Number of bytes needed to back the request.
+		///
+		/// This is `1` for a zero sized request, which is backed by a single byte.
+		requested: usize,
+		/// Number of bytes left before the end of the 32-bit address space.
+		available: usize,
+	},
+	/// The requested alignment is not a power of two of at least 8.
+	InvalidAlignment(u32),
+}
+
+/// Snapshot of the heap's usage statistics.
+///
+/// When serialized, the fields are exported under the stable metric names
+/// `used_bytes`, `peak_bytes`, `allocations`, `deallocations` and
+/// `allocated_bytes_total`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[cfg_attr(test, derive(serde_derive::Deserialize))]
+pub struct HeapStats {
+	/// Number of bytes currently handed out.
+	#[serde(rename = "used_bytes")]
+	pub used: usize,
+	/// Highest number of bytes handed out since the last `reset_peak`.
+	#[serde(rename = "peak_bytes")]
+	pub peak: usize,
+	/// Number of successful allocations over the heap's lifetime.
+	#[serde(rename = "allocations")]
+	pub alloc_count: u64,
+	/// Number of deallocations over the heap's lifetime.
+	#[serde(rename = "deallocations")]
+	pub dealloc_count: u64,
+	/// Number of bytes backing all successful allocations over the heap's lifetime.
+	#[serde(rename = "allocated_bytes_total")]
+	pub bytes_allocated_total: u64,
+}
+
+/// An allocator handing out blocks of the wasm linear memory.
+pub trait Allocator {
+	/// Allocate `size` bytes and return the offset of the allocated block.
+	fn allocate(&mut self, size: u32) -> Result<u32, AllocError>;
+
+	/// Deallocate the block at the given offset.
+	fn deallocate(&mut self, ptr: u32);
+
+	/// Number of bytes currently in use.
+	fn used(&self) -> usize;
+}
+
+/// Linear allocator handing out offsets into the wasm linear memory.
+///
+/// `Heap` is `Send`, so a prepared heap can be moved to another thread.
+/// Cloning a heap forks its allocation state; the copies advance independently.
+#[derive(Debug, Clone)]
 pub struct Heap {
+	label: Option<&'static str>,
+	reserved: u32,
 	end: u32,
 	total_size: u32,
+	peak: usize,
+	alloc_count: u64,
+	dealloc_count: u64,
+	bytes_allocated_total: u64,
 }
 
 impl Heap {
@@ -31,26 +99,590 @@ impl Heap {
 	///
 	/// This could mean that wasm binary specifies memory
 	/// limit and we are trying to allocate beyond that limit.
+	///
+	/// Offset `0` is always considered reserved, so that `0` can be used
+	/// to signal a failed allocation.
 	pub fn new(reserved: u32) -> Self {
+		let reserved = cmp::max(reserved, 1);
 		Heap {
+			label: None,
+			reserved,
 			end: reserved,
 			total_size: 0,
+			peak: 0,
+			alloc_count: 0,
+			dealloc_count: 0,
+			bytes_allocated_total: 0,
+		}
+	}
+
+	/// Construct new `Heap` struct whose log output is prefixed with `label`.
+	///
+	/// Useful to tell apart the output of several heaps living in one process.
+	pub fn with_label(reserved: u32, label: &'static str) -> Self {
+		Heap {
+			label: Some(label),
+			..Heap::new(reserved)
+		}
+	}
+
+	/// Allocate `size` bytes and return the offset of the allocated block.
+	///
+	/// A request for zero bytes is backed by a single byte, so that every
+	/// allocation gets a pointer distinct from all others.
+	///
+	/// Returns `Err` if the end of the block would not be addressable
+	/// with a `u32` pointer.
+	pub fn allocate(&mut self, size: u32) -> Result<u32, AllocError> {
+		self.bump(0, size)
+	}
+
+	/// Check whether `allocate(size)` would succeed, without changing the heap.
+	pub fn can_allocate(&self, size: u32) -> bool {
+		cmp::max(size, 1) as usize <= self.available()
+	}
+
+	/// Allocate `size` bytes at an offset which is a multiple of `align`.
+	///
+	/// `align` must be a power of two of at least 8. The bytes skipped to
+	/// reach the alignment are consumed and count towards `used`.
+	pub fn allocate_aligned(&mut self, size: u32, align: u32) -> Result<u32, AllocError> {
+		if !align.is_power_of_two() || align < 8 {
+			return Err(AllocError::InvalidAlignment(align));
 		}
+		// Distance from `end` to the next multiple of `align`.
+		let padding = self.end.wrapping_neg() & (align - 1);
+		self.bump(padding, size)
 	}
 
-	pub fn allocate(&mut self, size: u32) -> u32 {
-		let r = self.end;
-		self.end += size;
-		let new_total_size = r + size;
+	/// Skip `padding` bytes, then hand out a block of `size` bytes.
+	fn bump(&mut self, padding: u32, size: u32) -> Result<u32, AllocError> {
+		let size = cmp::max(size, 1);
+		let exhausted = || AllocError::HeapExhausted {
+			requested: size as usize,
+			available: self.available().saturating_sub(padding as usize),
+		};
+		let r = self.end.checked_add(padding).ok_or_else(exhausted)?;
+		let new_total_size = r.checked_add(size).ok_or_else(exhausted)?;
+		self.end = new_total_size;
 		if new_total_size > self.total_size {
 			if new_total_size / 1024  > self.total_size / 1024 {
-				trace!(target: "wasm-heap", "Allocated over {} MB",  new_total_size / 1024 / 1024);
+				match self.label {
+					Some(label) => trace!(target: "wasm-heap", "[{}] Allocated over {} MB", label, new_total_size / 1024 / 1024),
+					None => trace!(target: "wasm-heap", "Allocated over {} MB",  new_total_size / 1024 / 1024),
+				}
 			}
 			self.total_size = new_total_size;
 		}
-		r
+		if self.used() > self.peak {
+			self.peak = self.used();
+		}
+		self.alloc_count += 1;
+		self.bytes_allocated_total += u64::from(padding) + u64::from(size);
+		Ok(r)
+	}
+
+	/// Allocate up to `count` blocks of `size` bytes each.
+	///
+	/// Stops at the first failed allocation, so the returned vector may
+	/// hold fewer than `count` pointers.
+	pub fn allocate_many(&mut self, size: u32, count: usize) -> Vec<u32> {
+		// `count` is not bounded by what fits in the heap, so don't reserve it upfront.
+		let mut ptrs = Vec::new();
+		while ptrs.len() < count {
+			match self.allocate(size) {
+				Ok(ptr) => ptrs.push(ptr),
+				Err(_) => break,
+			}
+		}
+		ptrs
+	}
+
+	/// Allocate `size` bytes, returning `0` on failure.
+	///
+	/// This is meant for the runtime boundary (e.g. `ext_malloc`), which
+	/// can only pass a plain pointer back to the caller.
+	pub fn allocate_raw(&mut self, size: u32) -> u32 {
+		self.allocate(size).unwrap_or(0)
 	}
 
+	/// Allocate `size` bytes, returning `None` on failure.
+	pub fn allocate_nonzero(&mut self, size: u32) -> Option<NonZeroU32> {
+		self.allocate(size).ok()
+			.map(|ptr| NonZeroU32::new(ptr).expect("offset 0 is reserved in `Heap::new`; qed"))
+	}
+
+	/// Deallocate the block at the given offset.
+	///
+	/// The linear heap never reuses memory, so this is a no-op.
 	pub fn deallocate(&mut self, _offset: u32) {
+		self.dealloc_count += 1;
+	}
+
+	/// Forget all allocations, making the whole heap available again.
+	///
+	/// The `reserved` offset is kept, so the heap behaves like a freshly
+	/// constructed one. The peak usage and the lifetime counters are kept
+	/// as well; use `reset_peak` to clear the peak.
+	pub fn reset(&mut self) {
+		self.end = self.reserved;
+		self.total_size = 0;
+	}
+
+	/// Number of bytes handed out since the heap was constructed or last reset.
+	///
+	/// Memory is never given back by `deallocate`, so this only grows
+	/// until the next `reset`.
+	pub fn used(&self) -> usize {
+		(self.end - self.reserved) as usize
+	}
+
+	/// Number of bytes left before the end of the 32-bit address space.
+	///
+	/// This is address-space headroom rather than memory capacity: the wasm
+	/// instance may not be able to back that many bytes. It is the figure
+	/// reported by `AllocError::HeapExhausted` and checked by `can_allocate`.
+	pub fn available(&self) -> usize {
+		(u32::max_value() - self.end) as usize
+	}
+
+	/// Highest value `used` reached since construction or the last `reset_peak`.
+	pub fn peak_used(&self) -> usize {
+		self.peak
+	}
+
+	/// Restart peak tracking from the current usage.
+	///
+	/// Lets a host measure the peak of each runtime call separately.
+	pub fn reset_peak(&mut self) {
+		self.peak = self.used();
+	}
+
+	/// Number of successful allocations over the heap's lifetime.
+	pub fn allocations(&self) -> u64 {
+		self.alloc_count
+	}
+
+	/// Number of deallocations over the heap's lifetime.
+	pub fn deallocations(&self) -> u64 {
+		self.dealloc_count
+	}
+
+	/// Number of bytes backing all successful allocations over the heap's lifetime.
+	///
+	/// Zero sized requests count as the single byte backing them, and the
+	/// padding skipped by `allocate_aligned` counts as allocated, just as it
+	/// does for `used`.
+	pub fn total_bytes_allocated(&self) -> u64 {
+		self.bytes_allocated_total
+	}
+
+	/// Collect all usage statistics of the heap.
+	pub fn stats(&self) -> HeapStats {
+		HeapStats {
+			used: self.used(),
+			peak: self.peak,
+			alloc_count: self.alloc_count,
+			dealloc_count: self.dealloc_count,
+			bytes_allocated_total: self.bytes_allocated_total,
+		}
+	}
+
+	/// Serialize the current usage statistics as a compact JSON object.
+	pub fn stats_json(&self) -> String {
+		String::from_utf8(serializer::encode(&self.stats()))
+			.expect("JSON output is always valid UTF-8; qed")
+	}
+}
+
+impl Allocator for Heap {
+	fn allocate(&mut self, size: u32) -> Result<u32, AllocError> {
+		Heap::allocate(self, size)
+	}
+
+	fn deallocate(&mut self, ptr: u32) {
+		Heap::deallocate(self, ptr)
+	}
+
+	fn used(&self) -> usize {
+		Heap::used(self)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::{Mutex, Once};
+	use lazy_static::lazy_static;
+
+	lazy_static! {
+		static ref LOGGED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+	}
+
+	struct CapturingLogger;
+
+	impl log::Log for CapturingLogger {
+		fn enabled(&self, metadata: &log::Metadata) -> bool {
+			metadata.target() == "wasm-heap"
+		}
+
+		fn log(&self, record: &log::Record) {
+			if self.enabled(record.metadata()) {
+				LOGGED.lock().unwrap().push(format!("{}", record.args()));
+			}
+		}
+
+		fn flush(&self) {}
+	}
+
+	/// Install a logger capturing the heap's output and return everything logged so far.
+	fn logged() -> Vec<String> {
+		static INIT: Once = Once::new();
+		static LOGGER: CapturingLogger = CapturingLogger;
+		INIT.call_once(|| {
+			log::set_logger(&LOGGER).expect("no other logger is installed in the tests; qed");
+			log::set_max_level(log::LevelFilter::Trace);
+		});
+		LOGGED.lock().unwrap().clone()
+	}
+
+	#[test]
+	fn should_allocate_consecutive_blocks() {
+		let mut heap = Heap::new(8);
+
+		assert_eq!(heap.allocate(1), Ok(8));
+		assert_eq!(heap.allocate(10), Ok(9));
+		assert_eq!(heap.allocate(3), Ok(19));
+	}
+
+	#[test]
+	fn should_not_allocate_past_the_address_space() {
+		let mut heap = Heap::new(u32::max_value() - 16);
+
+		assert_eq!(heap.allocate(10), Ok(u32::max_value() - 16));
+		assert_eq!(heap.allocate(10), Err(AllocError::HeapExhausted { requested: 10, available: 6 }));
+		assert_eq!(heap.allocate(6), Ok(u32::max_value() - 6));
+	}
+
+	#[test]
+	fn should_allocate_exactly_filling_remaining_space() {
+		let mut heap = Heap::new(u32::max_value() - 2);
+
+		assert_eq!(heap.allocate(1), Ok(u32::max_value() - 2));
+		assert_eq!(heap.allocate(1), Ok(u32::max_value() - 1));
+		assert_eq!(heap.allocate(1), Err(AllocError::HeapExhausted { requested: 1, available: 0 }));
+	}
+
+	#[test]
+	fn should_predict_allocation_success() {
+		let mut heap = Heap::new(u32::max_value() - 16);
+		heap.allocate(10).unwrap();
+
+		assert!(!heap.can_allocate(7));
+		assert!(heap.allocate(7).is_err());
+		assert!(heap.can_allocate(6));
+		assert!(heap.allocate(6).is_ok());
+
+		assert!(!heap.can_allocate(0));
+		assert!(heap.allocate(0).is_err());
+		assert_eq!(heap.used(), 16);
+	}
+
+	#[test]
+	fn should_reject_max_size_without_overflow() {
+		let mut heap = Heap::new(1);
+
+		assert_eq!(
+			heap.allocate(u32::max_value()),
+			Err(AllocError::HeapExhausted { requested: u32::max_value() as usize, available: u32::max_value() as usize - 1 }),
+		);
+		assert!(heap.allocate_aligned(u32::max_value(), 8).is_err());
+		assert_eq!(heap.allocate_raw(u32::max_value()), 0);
+		assert_eq!(heap.used(), 0);
+	}
+
+	#[test]
+	fn allocate_raw_should_return_zero_on_failure() {
+		let mut heap = Heap::new(u32::max_value() - 4);
+
+		assert_eq!(heap.allocate_raw(4), u32::max_value() - 4);
+		assert_eq!(heap.allocate_raw(8), 0);
+	}
+
+	#[test]
+	fn should_give_zero_sized_allocations_distinct_pointers() {
+		let mut heap = Heap::new(8);
+
+		let first = heap.allocate(0).unwrap();
+		let second = heap.allocate(0).unwrap();
+		let third = heap.allocate(4).unwrap();
+		assert_eq!((first, second, third), (8, 9, 10));
+		assert_eq!(heap.used(), 6);
+
+		heap.deallocate(first);
+		heap.deallocate(second);
+	}
+
+	#[test]
+	fn should_never_allocate_offset_zero() {
+		let mut heap = Heap::new(0);
+
+		assert_eq!(heap.allocate(4), Ok(1));
+		assert_eq!(heap.used(), 4);
+		heap.reset();
+		assert_eq!(heap.allocate_nonzero(4), NonZeroU32::new(1));
+	}
+
+	#[test]
+	fn allocate_nonzero_should_return_none_on_failure() {
+		let mut heap = Heap::new(u32::max_value() - 4);
+
+		assert_eq!(heap.allocate_nonzero(4), NonZeroU32::new(u32::max_value() - 4));
+		assert_eq!(heap.allocate_nonzero(1), None);
+	}
+
+	#[test]
+	fn should_allocate_aligned_blocks() {
+		let mut heap = Heap::new(3);
+
+		let ptr = heap.allocate_aligned(10, 32).unwrap();
+		assert_eq!(ptr, 32);
+		let ptr = heap.allocate_aligned(1, 4096).unwrap();
+		assert_eq!(ptr, 4096);
+		assert_eq!(heap.used(), 4094);
+		assert_eq!(heap.total_bytes_allocated(), 4094);
+		heap.deallocate(ptr);
+
+		assert_eq!(heap.allocate(1), Ok(4097));
+		assert!(heap.total_bytes_allocated() >= heap.used() as u64);
+	}
+
+	#[test]
+	fn should_reject_invalid_alignment() {
+		let mut heap = Heap::new(8);
+
+		assert_eq!(heap.allocate_aligned(1, 4), Err(AllocError::InvalidAlignment(4)));
+		assert_eq!(heap.allocate_aligned(1, 24), Err(AllocError::InvalidAlignment(24)));
+		assert_eq!(heap.used(), 0);
+	}
+
+	#[test]
+	fn should_not_allocate_aligned_past_the_address_space() {
+		let mut heap = Heap::new(u32::max_value() - 16);
+
+		assert_eq!(
+			heap.allocate_aligned(1, 64),
+			Err(AllocError::HeapExhausted { requested: 1, available: 0 }),
+		);
+		assert_eq!(heap.used(), 0);
+	}
+
+	#[test]
+	fn should_allocate_many_until_full() {
+		let mut heap = Heap::new(u32::max_value() - 40);
+
+		let ptrs = heap.allocate_many(16, 5);
+		assert_eq!(ptrs, vec![u32::max_value() - 40, u32::max_value() - 24]);
+		assert_eq!(heap.used(), 32);
+		assert_eq!(heap.allocate_many(8, 1).len(), 1);
+	}
+
+	#[test]
+	fn should_not_reserve_more_than_fits_when_allocating_many() {
+		let mut heap = Heap::new(u32::max_value() - 4);
+
+		assert_eq!(heap.allocate_many(8, usize::max_value()), Vec::<u32>::new());
+		assert_eq!(heap.allocate_many(2, usize::max_value()), vec![u32::max_value() - 4, u32::max_value() - 2]);
+		assert_eq!(heap.used(), 4);
+	}
+
+	#[test]
+	fn should_report_used_bytes() {
+		let mut heap = Heap::new(100);
+		assert_eq!(heap.used(), 0);
+
+		heap.allocate(1).unwrap();
+		heap.allocate(9).unwrap();
+		heap.allocate(100).unwrap();
+		assert_eq!(heap.used(), 110);
+
+		heap.allocate(u32::max_value()).unwrap_err();
+		assert_eq!(heap.used(), 110);
+	}
+
+	#[test]
+	fn should_report_available_address_space() {
+		let mut heap = Heap::new(u32::max_value() - 16);
+		assert_eq!(heap.available(), 16);
+
+		heap.allocate(10).unwrap();
+		assert_eq!(heap.available(), 6);
+		assert_eq!(heap.allocate(7), Err(AllocError::HeapExhausted { requested: 7, available: 6 }));
+
+		heap.allocate(6).unwrap();
+		assert_eq!(heap.available(), 0);
+	}
+
+	#[test]
+	fn should_track_peak_usage() {
+		let mut heap = Heap::new(0);
+
+		heap.allocate(32).unwrap();
+		heap.allocate(16).unwrap();
+		assert_eq!(heap.peak_used(), 48);
+
+		heap.reset_peak();
+		assert_eq!(heap.peak_used(), heap.used());
+
+		heap.allocate(8).unwrap();
+		assert_eq!(heap.peak_used(), 56);
+	}
+
+	#[test]
+	fn should_behave_like_new_heap_after_reset() {
+		let mut heap = Heap::new(16);
+		heap.allocate(10).unwrap();
+		heap.allocate(20).unwrap();
+
+		heap.reset();
+
+		let mut fresh = Heap::new(16);
+		assert_eq!(heap.used(), 0);
+		assert_eq!(heap.allocate(1), fresh.allocate(1));
+		assert_eq!(heap.allocate(5), fresh.allocate(5));
+		assert_eq!(heap.peak_used(), 30);
+	}
+
+	#[test]
+	fn should_format_occupancy() {
+		let mut heap = Heap::new(8);
+		heap.allocate(16).unwrap();
+		heap.allocate(16).unwrap();
+
+		let formatted = format!("{:?}", heap);
+		assert!(formatted.contains("reserved: 8"));
+		assert!(formatted.contains("end: 40"));
+		assert!(formatted.contains("peak: 32"));
+	}
+
+	#[test]
+	fn should_count_operations_over_lifetime() {
+		let mut heap = Heap::new(0);
+		let first = heap.allocate(0).unwrap();
+		let second = heap.allocate(10).unwrap();
+		heap.allocate(u32::max_value()).unwrap_err();
+		heap.deallocate(first);
+
+		heap.reset();
+		heap.allocate(5).unwrap();
+		heap.deallocate(second);
+
+		assert_eq!(heap.allocations(), 3);
+		assert_eq!(heap.deallocations(), 2);
+		assert_eq!(heap.total_bytes_allocated(), 16);
+	}
+
+	#[test]
+	fn should_prefix_log_output_with_label() {
+		logged();
+		let mut heap = Heap::with_label(0, "test-heap");
+		heap.allocate(2048).unwrap();
+
+		assert!(logged().iter().any(|line| line == "[test-heap] Allocated over 0 MB"));
+	}
+
+	#[test]
+	fn should_collect_stats() {
+		let mut heap = Heap::new(8);
+		let ptr = heap.allocate(16).unwrap();
+		heap.allocate(0).unwrap();
+		heap.deallocate(ptr);
+		heap.reset();
+		heap.allocate(4).unwrap();
+
+		assert_eq!(heap.stats(), HeapStats {
+			used: 4,
+			peak: 17,
+			alloc_count: 3,
+			dealloc_count: 1,
+			bytes_allocated_total: 21,
+		});
+	}
+
+	#[test]
+	fn should_export_stats_as_json() {
+		let mut heap = Heap::new(8);
+		heap.allocate(16).unwrap();
+		let ptr = heap.allocate(3).unwrap();
+		heap.deallocate(ptr);
+
+		let json = heap.stats_json();
+		assert!(json.starts_with("{\"used_bytes\":19,"));
+		assert_eq!(serializer::from_str::<HeapStats>(&json).unwrap(), heap.stats());
+	}
+
+	#[test]
+	fn heap_should_be_send() {
+		fn assert_send<T: Send>() {}
+		assert_send::<Heap>();
+	}
+
+	#[test]
+	fn should_allocate_independently_after_clone() {
+		let mut heap = Heap::new(8);
+		assert_eq!(heap.allocate(4), Ok(8));
+
+		let mut fork = heap.clone();
+		assert_eq!(heap.allocate(16), Ok(12));
+		assert_eq!(fork.allocate(2), Ok(12));
+		assert_eq!(fork.allocate(2), Ok(14));
+
+		assert_eq!(heap.used(), 20);
+		assert_eq!(fork.used(), 8);
+		assert_eq!(heap.allocations(), 2);
+		assert_eq!(fork.allocations(), 3);
+	}
+
+	/// Allocator with a fixed capacity which never reuses memory.
+	struct BumpOnly {
+		next: u32,
+		capacity: u32,
+	}
+
+	impl Allocator for BumpOnly {
+		fn allocate(&mut self, size: u32) -> Result<u32, AllocError> {
+			let available = self.capacity - self.next;
+			if size > available {
+				return Err(AllocError::HeapExhausted { requested: size as usize, available: available as usize });
+			}
+			self.next += size;
+			Ok(self.next - size)
+		}
+
+		fn deallocate(&mut self, _ptr: u32) {
+		}
+
+		fn used(&self) -> usize {
+			self.next as usize
+		}
+	}
+
+	fn allocate_all<A: Allocator>(allocator: &mut A, sizes: &[u32]) -> Result<Vec<u32>, AllocError> {
+		sizes.iter().map(|size| allocator.allocate(*size)).collect()
+	}
+
+	#[test]
+	fn should_allocate_through_allocator_trait() {
+		let mut heap = Heap::new(1);
+		assert_eq!(allocate_all(&mut heap, &[4, 8, 2]), Ok(vec![1, 5, 13]));
+		assert_eq!(Allocator::used(&heap), 14);
+
+		let mut bump = BumpOnly { next: 0, capacity: 12 };
+		assert_eq!(allocate_all(&mut bump, &[4, 8]), Ok(vec![0, 4]));
+		assert_eq!(
+			allocate_all(&mut bump, &[1]),
+			Err(AllocError::HeapExhausted { requested: 1, available: 0 }),
+		);
+		assert_eq!(bump.used(), 12);
 	}
 }
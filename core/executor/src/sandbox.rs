@@ -149,7 +149,11 @@ pub trait SandboxCapabilities {
 	/// Allocate space of the specified length in the supervisor memory.
 	///
 	/// Returns pointer to the allocated block.
-	fn allocate(&mut self, len: u32) -> u32;
+	///
+	/// # Errors
+	///
+	/// Returns `Err` if the supervisor heap can't fit `len` bytes.
+	fn allocate(&mut self, len: u32) -> Result<u32, UserError>;
 
 	/// Deallocate space specified by the pointer that was previously returned by [`allocate`].
 	///
@@ -232,7 +236,7 @@ impl<'a, FE: SandboxCapabilities + Externals + 'a> Externals for GuestExternals<
 		// Move serialized arguments inside the memory and invoke dispatch thunk and
 		// then free allocated memory.
 		let invoke_args_ptr = self.supervisor_externals
-			.allocate(invoke_args_data.len() as u32);
+			.allocate(invoke_args_data.len() as u32)?;
 		self.supervisor_externals
 			.write_memory(invoke_args_ptr, &invoke_args_data)?;
 		let result = ::wasmi::FuncInstance::invoke(